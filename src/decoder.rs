@@ -0,0 +1,139 @@
+use std::marker::PhantomData;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::Message;
+
+/// Decouples `MessageStream` from any one wire format. The stream only
+/// needs to know how big the length prefix is, how to read a length out of
+/// it, and how to turn the payload bytes into an `Item` — everything else
+/// (buffering, sector-alignment padding, resumption across `Poll::Pending`)
+/// is handled generically by the stream.
+pub trait Decoder {
+    type Item;
+
+    /// Upper bound, in bytes, on the length prefix. Fixed-width formats
+    /// return their exact size; variable-width formats (e.g. LEB128
+    /// varints) return the largest a prefix could ever be.
+    fn length_prefix_size() -> usize;
+
+    /// Whether `buf` (the length-prefix bytes read so far) is complete.
+    /// The default assumes a fixed-width prefix; variable-width formats
+    /// override this to detect their own terminal condition.
+    fn is_length_prefix_complete(buf: &[u8]) -> bool {
+        buf.len() >= Self::length_prefix_size()
+    }
+
+    /// Whether the length prefix always occupies exactly
+    /// `length_prefix_size()` bytes. Fixed-width formats are read in a
+    /// single bulk read; variable-width formats (e.g. LEB128 varints)
+    /// override this to `false` and are read one byte at a time, since a
+    /// continuation bit rather than a fixed count marks completion.
+    fn is_fixed_width() -> bool {
+        true
+    }
+
+    fn decode_length(buf: &[u8]) -> u64;
+
+    /// Size, in bytes, of an explicit on-disk padding field written
+    /// immediately after the length prefix, or `0` if this format carries
+    /// no such field — in which case `MessageStream` falls back to
+    /// deriving padding from sector alignment. Formats that round-trip
+    /// through `MessageSink` (the `LeU32Length` default) store the padding
+    /// count explicitly so the reader never has to re-derive it.
+    fn padding_field_size() -> usize {
+        0
+    }
+
+    fn decode_padding(buf: &[u8]) -> u64 {
+        let _ = buf;
+        0
+    }
+
+    fn decode_item(buf: &[u8]) -> Result<Self::Item, std::io::Error>;
+}
+
+/// 4-byte little-endian length prefix, followed by a 4-byte little-endian
+/// padding count — the format `MessageSink` writes and `MessageStream`
+/// uses by default. The padding count is trusted from disk rather
+/// than re-derived from sector alignment, so a reader resuming mid-record
+/// never has to recompute where the sector boundary falls.
+pub struct LeU32Length<T>(PhantomData<T>);
+
+impl Decoder for LeU32Length<Message> {
+    type Item = Message;
+
+    fn length_prefix_size() -> usize {
+        4
+    }
+
+    fn decode_length(buf: &[u8]) -> u64 {
+        LittleEndian::read_u32(buf) as u64
+    }
+
+    fn padding_field_size() -> usize {
+        4
+    }
+
+    fn decode_padding(buf: &[u8]) -> u64 {
+        LittleEndian::read_u32(buf) as u64
+    }
+
+    fn decode_item(buf: &[u8]) -> Result<Message, std::io::Error> {
+        Ok(Message::from_bytes(buf))
+    }
+}
+
+/// 4-byte network-order (big-endian) length prefix, as used by async-prost.
+pub struct NetworkEndianU32<T>(PhantomData<T>);
+
+impl Decoder for NetworkEndianU32<Message> {
+    type Item = Message;
+
+    fn length_prefix_size() -> usize {
+        4
+    }
+
+    fn decode_length(buf: &[u8]) -> u64 {
+        BigEndian::read_u32(buf) as u64
+    }
+
+    fn decode_item(buf: &[u8]) -> Result<Message, std::io::Error> {
+        Ok(Message::from_bytes(buf))
+    }
+}
+
+/// LEB128 varint length prefix, as used by protobuf's delimited wire format.
+pub struct VarintDelimited<T>(PhantomData<T>);
+
+impl Decoder for VarintDelimited<Message> {
+    type Item = Message;
+
+    fn length_prefix_size() -> usize {
+        // A u64 LEB128 varint never needs more than 10 bytes.
+        10
+    }
+
+    fn is_length_prefix_complete(buf: &[u8]) -> bool {
+        matches!(buf.last(), Some(byte) if byte & 0x80 == 0)
+    }
+
+    fn is_fixed_width() -> bool {
+        false
+    }
+
+    fn decode_length(buf: &[u8]) -> u64 {
+        let mut value = 0u64;
+        for (i, byte) in buf.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (i * 7);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn decode_item(buf: &[u8]) -> Result<Message, std::io::Error> {
+        Ok(Message::from_bytes(buf))
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use futures::{AsyncBufRead, AsyncSeek, AsyncSeekExt};
+
+use crate::{
+    decoder::Decoder,
+    stream::MessageStream,
+};
+
+/// One entry in the sparse offset index: a monotonic record number mapped to
+/// the sector-aligned byte offset of that record, plus its padded on-disk
+/// length and an optional user key. Fixed-width so the index itself can be
+/// mmap'd or streamed without a separate length table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub record_no: u64,
+    pub offset: u64,
+    pub padded_len: u64,
+    pub key: Option<u64>,
+}
+
+impl IndexEntry {
+    /// record_no, offset, padded_len, a has_key flag, and the key itself —
+    /// five `u64`s. The flag is its own field rather than a sentinel key
+    /// value, so a record whose real key happens to collide with the
+    /// sentinel can't silently be read back as keyless.
+    pub const ENCODED_SIZE: usize = 40;
+
+    pub fn to_bytes(self) -> [u8; Self::ENCODED_SIZE] {
+        let mut buf = [0u8; Self::ENCODED_SIZE];
+        buf[0..8].copy_from_slice(&self.record_no.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.padded_len.to_le_bytes());
+        buf[24..32].copy_from_slice(&(self.key.is_some() as u64).to_le_bytes());
+        buf[32..40].copy_from_slice(&self.key.unwrap_or(0).to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let record_no = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        let offset = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let padded_len = u64::from_le_bytes(buf[16..24].try_into().unwrap());
+        let has_key = u64::from_le_bytes(buf[24..32].try_into().unwrap()) != 0;
+        let key = u64::from_le_bytes(buf[32..40].try_into().unwrap());
+        Self {
+            record_no,
+            offset,
+            padded_len,
+            key: has_key.then_some(key),
+        }
+    }
+}
+
+/// An in-memory sparse offset index: maps record numbers (and optionally
+/// user keys) to the sector-aligned byte offset of that record in a
+/// direct-I/O log. Entries are appended in record order, so `seek_to` can
+/// binary search instead of scanning.
+#[derive(Default)]
+pub struct OffsetIndex {
+    entries: Vec<IndexEntry>,
+    by_key: HashMap<u64, usize>,
+}
+
+impl OffsetIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes an index previously persisted via `to_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut entries = Vec::with_capacity(buf.len() / IndexEntry::ENCODED_SIZE);
+        let mut by_key = HashMap::new();
+        for chunk in buf.chunks_exact(IndexEntry::ENCODED_SIZE) {
+            let entry = IndexEntry::from_bytes(chunk);
+            if let Some(key) = entry.key {
+                by_key.insert(key, entries.len());
+            }
+            entries.push(entry);
+        }
+        Self { entries, by_key }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.entries.len() * IndexEntry::ENCODED_SIZE);
+        for entry in &self.entries {
+            buf.extend_from_slice(&entry.to_bytes());
+        }
+        buf
+    }
+
+    /// Append-time hook: record where `record_no` landed as it's written.
+    /// `MessageSink::drain_appended` is the intended source of these three
+    /// values.
+    pub fn append(&mut self, record_no: u64, offset: u64, padded_len: u64, key: Option<u64>) {
+        if let Some(key) = key {
+            self.by_key.insert(key, self.entries.len());
+        }
+        self.entries.push(IndexEntry {
+            record_no,
+            offset,
+            padded_len,
+            key,
+        });
+    }
+
+    /// Returns the sector-aligned byte offset of `record_no`, if indexed.
+    pub fn seek_to(&self, record_no: u64) -> Option<u64> {
+        self.entries
+            .binary_search_by_key(&record_no, |e| e.record_no)
+            .ok()
+            .map(|i| self.entries[i].offset)
+    }
+
+    /// Returns the sector-aligned byte offset of the record last appended
+    /// under `key`, if any.
+    pub fn seek_to_key(&self, key: u64) -> Option<u64> {
+        self.by_key.get(&key).map(|&i| self.entries[i].offset)
+    }
+}
+
+/// Pairs an `OffsetIndex` with the seekable reader it indexes, so callers
+/// can jump straight to a record's sector-aligned offset instead of
+/// scanning `MessageStream` from the start of the log.
+pub struct IndexedLogReader<R> {
+    index: OffsetIndex,
+    /// `None` once `stream_from` has handed the reader off to a
+    /// `MessageStream` on a hit. Kept as `Some` on a miss, so a failed
+    /// lookup never loses the open reader.
+    reader: Option<R>,
+}
+
+impl<R> IndexedLogReader<R>
+where
+    R: AsyncBufRead + AsyncSeek + Unpin,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            index: OffsetIndex::new(),
+            reader: Some(reader),
+        }
+    }
+
+    pub fn with_index(reader: R, index: OffsetIndex) -> Self {
+        Self {
+            index,
+            reader: Some(reader),
+        }
+    }
+
+    pub fn index(&self) -> &OffsetIndex {
+        &self.index
+    }
+
+    pub fn index_mut(&mut self) -> &mut OffsetIndex {
+        &mut self.index
+    }
+
+    /// Seeks the underlying reader to `record_no`'s sector-aligned offset
+    /// and returns a `MessageStream` ready to decode starting there, taking
+    /// ownership of the reader in the process. Returns `Ok(None)` without
+    /// touching `self` if `record_no` isn't indexed, so a miss doesn't lose
+    /// the open reader the way consuming `self` would have.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `record_no` is indexed and a previous call already
+    /// returned a `MessageStream`.
+    pub async fn stream_from<D>(
+        &mut self,
+        record_no: u64,
+        sector_size: u64,
+    ) -> Result<Option<MessageStream<R, D>>, std::io::Error>
+    where
+        D: Decoder,
+    {
+        let Some(offset) = self.index.seek_to(record_no) else {
+            return Ok(None);
+        };
+        self.reader
+            .as_mut()
+            .expect("IndexedLogReader's reader was already handed off by a prior stream_from")
+            .seek(std::io::SeekFrom::Start(offset))
+            .await?;
+        let reader = self.reader.take().unwrap();
+        Ok(Some(MessageStream::new(reader, sector_size)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+    use crate::{decoder::LeU32Length, Message};
+
+    #[test]
+    fn index_entry_round_trips_key_at_sentinel_value() {
+        let entry = IndexEntry {
+            record_no: 7,
+            offset: 128,
+            padded_len: 64,
+            key: Some(u64::MAX),
+        };
+
+        let decoded = IndexEntry::from_bytes(&entry.to_bytes());
+
+        assert_eq!(decoded, entry);
+        assert_eq!(decoded.key, Some(u64::MAX));
+    }
+
+    #[test]
+    fn index_entry_round_trips_absent_key() {
+        let entry = IndexEntry {
+            record_no: 1,
+            offset: 0,
+            padded_len: 64,
+            key: None,
+        };
+
+        assert_eq!(IndexEntry::from_bytes(&entry.to_bytes()), entry);
+    }
+
+    #[test]
+    fn offset_index_persists_sentinel_key_through_to_bytes() {
+        let mut index = OffsetIndex::new();
+        index.append(0, 0, 64, Some(u64::MAX));
+
+        let reloaded = OffsetIndex::from_bytes(&index.to_bytes());
+
+        assert_eq!(reloaded.seek_to(0), Some(0));
+        assert_eq!(reloaded.seek_to_key(u64::MAX), Some(0));
+    }
+
+    #[test]
+    fn stream_from_leaves_reader_in_place_on_miss() {
+        futures::executor::block_on(async {
+            let mut log = IndexedLogReader::new(Cursor::new(Vec::<u8>::new()));
+
+            let result = log
+                .stream_from::<LeU32Length<Message>>(0, 64)
+                .await
+                .unwrap();
+            assert!(result.is_none());
+
+            // The reader wasn't handed off on the miss, so a second call
+            // doesn't panic and can still look up a (now-indexed) record.
+            log.index_mut().append(0, 0, 64, None);
+            let result = log
+                .stream_from::<LeU32Length<Message>>(0, 64)
+                .await
+                .unwrap();
+            assert!(result.is_some());
+        });
+    }
+}
@@ -0,0 +1,201 @@
+use std::{collections::VecDeque, pin::Pin, task::Poll};
+
+use futures::{AsyncWrite, AsyncWriteExt, FutureExt, Sink};
+use monoio::io::BufWriter;
+use pin_project::pin_project;
+
+use crate::{dma_buf, Message};
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+const PADDING_FIELD_SIZE: usize = 4;
+
+/// Encodes `message` as a 4-byte little-endian length prefix, a 4-byte
+/// little-endian padding count, then the payload, zero-padded up to the
+/// next `sector_size` boundary — the layout `MessageStream`'s default
+/// `LeU32Length` decoder expects. Storing the padding count explicitly
+/// means a reader never has to re-derive it from sector alignment.
+fn encode(message: &Message, sector_size: u64) -> Vec<u8> {
+    let payload = message.to_bytes();
+    let total = (LENGTH_PREFIX_SIZE + PADDING_FIELD_SIZE + payload.len()) as u64;
+    let aligned = dma_buf::val_align_up(total, sector_size);
+    let padding = (aligned - total) as u32;
+
+    let mut buf = Vec::with_capacity(aligned as usize);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&padding.to_le_bytes());
+    buf.extend_from_slice(&payload);
+    buf.resize(aligned as usize, 0);
+    buf
+}
+
+struct PendingWrite {
+    buf: Vec<u8>,
+    written: usize,
+    record_no: u64,
+    offset: u64,
+}
+
+/// `(record_no, offset, padded_len)` for a record that finished flushing.
+/// Callers maintaining an `IndexedLogReader` drain these after each
+/// `poll_ready`/`poll_flush` and feed them into the index's `append` hook.
+pub type AppendedRecord = (u64, u64, u64);
+
+/// A sink that writes `Message`s as length-prefixed, sector-aligned records,
+/// mirroring the layout `MessageStream` reads back. `sector_size` must match
+/// the value passed to the paired `MessageStream` for the stream to round-trip.
+#[pin_project]
+pub struct MessageSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    sector_size: u64,
+    bytes_written: u64,
+    next_record_no: u64,
+    /// Every record flushed since the last `drain_appended` call. A
+    /// `Sink` can flush more than once between two calls into user code
+    /// (e.g. `SinkExt::send_all`), so this has to be a queue rather than a
+    /// single overwritable slot, or all but the last flushed record would
+    /// silently go missing from the index.
+    appended: VecDeque<AppendedRecord>,
+    pending: Option<PendingWrite>,
+    #[pin]
+    writer: BufWriter<W>,
+}
+
+impl<W> MessageSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W, sector_size: u64) -> Self {
+        Self {
+            sector_size,
+            bytes_written: 0,
+            next_record_no: 0,
+            appended: VecDeque::new(),
+            pending: None,
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    /// Drains every `(record_no, offset, padded_len)` recorded since the
+    /// last call, oldest first. Feed these into `OffsetIndex::append` (e.g.
+    /// via `IndexedLogReader::index_mut`) to keep the sparse index in sync with what was actually written.
+    pub fn drain_appended(&mut self) -> impl Iterator<Item = AppendedRecord> + '_ {
+        self.appended.drain(..)
+    }
+}
+
+impl<W> Sink<Message> for MessageSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    type Error = std::io::Error;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        debug_assert!(
+            this.pending.is_none(),
+            "start_send called before poll_ready drained the pending record"
+        );
+        let record_no = *this.next_record_no;
+        *this.next_record_no += 1;
+        *this.pending = Some(PendingWrite {
+            buf: encode(&item, *this.sector_size),
+            written: 0,
+            record_no,
+            offset: *this.bytes_written,
+        });
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_flush_pending(cx))?;
+        let this = self.project();
+        this.writer.flush().poll_unpin(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_flush(cx))?;
+        let this = self.project();
+        this.writer.close().poll_unpin(cx)
+    }
+}
+
+impl<W> MessageSink<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Drains `self.pending`, if any, fully into the underlying writer.
+    fn poll_flush_pending(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.project();
+        if let Some(pending) = this.pending {
+            while pending.written < pending.buf.len() {
+                let n = futures::ready!(this
+                    .writer
+                    .write(&pending.buf[pending.written..])
+                    .poll_unpin(cx))?;
+                if n == 0 {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "failed to write whole record",
+                    )));
+                }
+                pending.written += n;
+            }
+            let pending = this.pending.take().unwrap();
+            *this.bytes_written += pending.buf.len() as u64;
+            this.appended
+                .push_back((pending.record_no, pending.offset, pending.buf.len() as u64));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{io::Cursor, SinkExt};
+
+    use super::*;
+
+    const SECTOR_SIZE: u64 = 64;
+
+    #[test]
+    fn drain_appended_yields_every_record_flushed_since_the_last_drain() {
+        futures::executor::block_on(async {
+            let mut sink = MessageSink::new(Cursor::new(Vec::new()), SECTOR_SIZE);
+
+            // Several feeds followed by a single flush must record every
+            // appended record, not only the last one: poll_ready flushes the previous
+            // pending write, so a send-loop drains through poll_ready, not
+            // through an explicit flush per record.
+            sink.feed(Message::from_bytes(b"one")).await.unwrap();
+            sink.feed(Message::from_bytes(b"two")).await.unwrap();
+            sink.feed(Message::from_bytes(b"three")).await.unwrap();
+            sink.flush().await.unwrap();
+
+            let appended: Vec<_> = sink.drain_appended().collect();
+            assert_eq!(appended.len(), 3);
+            assert_eq!(appended[0].0, 0);
+            assert_eq!(appended[1].0, 1);
+            assert_eq!(appended[2].0, 2);
+
+            assert_eq!(sink.drain_appended().count(), 0);
+        });
+    }
+}
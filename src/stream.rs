@@ -1,10 +1,11 @@
-use std::{pin::Pin, task::Poll};
+use std::{marker::PhantomData, pin::Pin, task::Poll};
 
 use futures::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, FutureExt, Stream};
 use monoio::{buf::IoBufMut, io::BufReader};
 use pin_project::pin_project;
 
 use crate::{
+    decoder::{Decoder, LeU32Length},
     dma_buf::{self, IoBuf},
     Message,
 };
@@ -20,201 +21,470 @@ where
     ) -> impl Stream<Item = Result<Buf, std::io::Error>>;
 }
 
+/// Default scratch capacity for `MessageStream::new`: one sector is almost
+/// always enough for the length prefix plus a typical payload, and `ensure_capacity`
+/// grows it on the rare record that doesn't fit.
+const DEFAULT_SCRATCH_SECTORS: u64 = 1;
+
 #[pin_project]
-pub struct MessageStream<R>
+pub struct MessageStream<R, D = LeU32Length<Message>>
 where
     R: AsyncBufRead + Unpin,
+    D: Decoder,
 {
     sector_size: u64,
-    read_bytes: u64,
-    message_length: u32,
     state: State,
+    /// Reusable scratch buffer the length prefix and payload are read into
+    /// in place. Reused across every record so steady-state decoding does
+    /// not allocate; only grows when a record larger than the current
+    /// capacity shows up.
+    scratch: Vec<u8>,
     #[pin]
     reader: R,
+    _decoder: PhantomData<D>,
 }
 
-impl<R> MessageStream<R>
+impl<R, D> MessageStream<R, D>
 where
     R: AsyncBufRead + Unpin,
+    D: Decoder,
 {
     pub fn new(reader: R, sector_size: u64) -> Self {
+        Self::with_capacity(reader, sector_size, (sector_size * DEFAULT_SCRATCH_SECTORS) as usize)
+    }
+
+    /// Like `new`, but preallocates `capacity` bytes of scratch space up
+    /// front instead of a single sector.
+    pub fn with_capacity(reader: R, sector_size: u64, capacity: usize) -> Self {
+        Self::with_buffer(reader, sector_size, vec![0u8; capacity])
+    }
+
+    /// Like `new`, but reuses a caller-provided buffer as scratch space —
+    /// e.g. one allocated with direct-I/O alignment and sized to the
+    /// caller's max expected batch, to avoid the stream ever reallocating.
+    pub fn with_buffer(reader: R, sector_size: u64, buffer: Vec<u8>) -> Self {
         Self {
-            read_bytes: 0,
             state: State::Ready,
-            message_length: 0,
             sector_size,
+            scratch: buffer,
             reader,
+            _decoder: PhantomData,
         }
     }
 }
 
-#[derive(Copy, Clone)]
-enum Reading {
-    Length,
-    Message,
-}
-
 enum State {
     Ready,
-    Pending(Reading, usize, Vec<u8>),
+    ReadingPrefix {
+        filled: usize,
+    },
+    /// Only entered when `Decoder::padding_field_size()` is non-zero: reads
+    /// the explicit on-disk padding count that immediately follows the
+    /// length prefix, so the trailer can be trusted instead of re-derived
+    /// from sector alignment.
+    ReadingPaddingField {
+        prefix_len: usize,
+        payload_len: usize,
+        filled: usize,
+    },
+    ReadingPayload {
+        payload_start: usize,
+        payload_len: usize,
+        filled: usize,
+        /// `Some(padding)` when the decoder supplied an explicit on-disk
+        /// padding count; `None` means padding must be derived from sector
+        /// alignment once the payload length is known.
+        explicit_padding: Option<u64>,
+    },
+    /// The payload has been buffered, but the trailing padding bytes needed
+    /// to reach the next sector boundary have not been fully drained and
+    /// verified yet. The item isn't decoded until this completes, so that
+    /// resuming after `Poll::Pending` always leaves the reader exactly on a
+    /// sector boundary and never hands out a record whose trailer turned
+    /// out to be corrupt.
+    Trailing {
+        remaining: usize,
+        payload_start: usize,
+        payload_len: usize,
+    },
+}
+
+fn ensure_capacity(buf: &mut Vec<u8>, needed: usize) {
+    if buf.len() < needed {
+        buf.resize(needed, 0);
+    }
+}
+
+/// Reads exactly `remaining` padding bytes off `reader`, verifying each one
+/// is `0`. Returns `Poll::Pending` (with `remaining` updated to reflect
+/// partial progress) if the underlying reader isn't ready yet.
+fn drain_padding<R>(
+    mut reader: Pin<&mut R>,
+    remaining: &mut usize,
+    cx: &mut std::task::Context<'_>,
+) -> Poll<Result<(), std::io::Error>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut chunk = [0u8; 64];
+    while *remaining > 0 {
+        let n = (*remaining).min(chunk.len());
+        let read = match reader.read(&mut chunk[..n]).poll_unpin(cx)? {
+            Poll::Ready(read) => read,
+            Poll::Pending => return Poll::Pending,
+        };
+        if read == 0 {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "EOF reached while reading trailer padding",
+            )));
+        }
+        if chunk[..read].iter().any(|&b| b != 0) {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "non-zero byte in trailer padding",
+            )));
+        }
+        *remaining -= read;
+    }
+    Poll::Ready(Ok(()))
 }
 
-impl<R> Stream for MessageStream<R>
+impl<R, D> Stream for MessageStream<R, D>
 where
     R: AsyncBufRead + Unpin,
+    D: Decoder,
 {
-    type Item = Result<Message, std::io::Error>;
+    type Item = Result<D::Item, std::io::Error>;
 
     fn poll_next(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
-        let state = std::mem::replace(this.state, State::Ready);
-
-        let mut read_exact = |reading: Reading,
-                              buf: &mut [u8],
-                              cx: &mut std::task::Context<'_>|
-         -> Poll<Result<(), std::io::Error>> {
-            let mut read_offset = 0;
-            while read_offset < buf.len() {
-                let n = match this.reader.read(&mut buf[read_offset..]).poll_unpin(cx)? {
-                    Poll::Ready(val) => val,
-                    Poll::Pending => {
-                        let len = buf.len();
-                        let mut new_buf = vec![0; len];
-                        new_buf.copy_from_slice(&buf);
-                        *this.state = State::Pending(reading, read_offset, new_buf);
-                        return Poll::Pending;
-                    }
-                };
+        let mut state = std::mem::replace(this.state, State::Ready);
 
-                if n == 0 {
-                    return Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::UnexpectedEof,
-                        "EOF reached",
-                    )));
+        loop {
+            match state {
+                State::Ready => {
+                    ensure_capacity(this.scratch, D::length_prefix_size());
+                    state = State::ReadingPrefix { filled: 0 };
                 }
-                read_offset += n;
-            }
-            Poll::Ready(Ok(()))
-        };
+                State::ReadingPrefix { mut filled } => {
+                    let target = D::length_prefix_size();
 
-        match state {
-            State::Ready => {}
-            State::Pending(reading, read, mut buf) => {
-                match reading {
-                    Reading::Length => {
-                        if let Err(e) =
-                            futures::ready!(read_exact(Reading::Length, &mut buf[read..], cx))
-                        {
-                            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                return Poll::Ready(None);
+                    if D::is_fixed_width() {
+                        // Fixed-width prefixes are always exactly `target`
+                        // bytes, so read them in as few calls as possible
+                        // instead of one byte at a time.
+                        while filled < target {
+                            let n = match this
+                                .reader
+                                .read(&mut this.scratch[filled..target])
+                                .poll_unpin(cx)?
+                            {
+                                Poll::Ready(n) => n,
+                                Poll::Pending => {
+                                    *this.state = State::ReadingPrefix { filled };
+                                    return Poll::Pending;
+                                }
+                            };
+                            if n == 0 {
+                                if filled == 0 {
+                                    return Poll::Ready(None);
+                                }
+                                return Poll::Ready(Some(Err(std::io::Error::new(
+                                    std::io::ErrorKind::UnexpectedEof,
+                                    "EOF reached while reading length prefix",
+                                ))));
                             }
-                            return Some(Err(e.into())).into();
+                            filled += n;
                         }
-                        let length = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-                        *this.message_length = length;
-
-                        let mut payload = vec![0u8; length as _];
-                        if let Err(e) =
-                            futures::ready!(read_exact(Reading::Message, &mut payload, cx))
-                        {
-                            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                return Poll::Ready(None);
+                    } else {
+                        // Variable-width prefixes (e.g. LEB128 varints) have
+                        // to be read one byte at a time: only the decoder
+                        // knows, after each byte, whether the prefix is done.
+                        while !D::is_length_prefix_complete(&this.scratch[..filled]) {
+                            if filled >= target {
+                                return Poll::Ready(Some(Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    "length prefix exceeded its maximum size",
+                                ))));
                             }
-                            return Some(Err(e.into())).into();
-                        }
-                        *this.read_bytes += length as u64 + 4;
-                        if *this.read_bytes >= *this.message_length as u64 {
-                            // This is a temp solution, to the padding that Direct I/O requires.
-                            // Later on, we could encode that information in our batch header
-                            // for example Header { batch_length: usize, padding: usize }
-                            // and use the padding to advance the reader further.
-                            /*
-                            let total_batch_length = *this.batch_length + RETAINED_BATCH_OVERHEAD;
-                            let adjusted_size = io::val_align_up(total_batch_length, *this.sector_size);
-                            */
-                            let total_batch_length = (*this.message_length + 4) as u64;
-                            let adjusted_size =
-                                dma_buf::val_align_up(total_batch_length, *this.sector_size);
-                            let diff = adjusted_size - total_batch_length;
-                            this.reader.consume_unpin(diff as _);
-                            *this.message_length = 0;
+                            let n = match this
+                                .reader
+                                .read(&mut this.scratch[filled..filled + 1])
+                                .poll_unpin(cx)?
+                            {
+                                Poll::Ready(n) => n,
+                                Poll::Pending => {
+                                    *this.state = State::ReadingPrefix { filled };
+                                    return Poll::Pending;
+                                }
+                            };
+                            if n == 0 {
+                                if filled == 0 {
+                                    return Poll::Ready(None);
+                                }
+                                return Poll::Ready(Some(Err(std::io::Error::new(
+                                    std::io::ErrorKind::UnexpectedEof,
+                                    "EOF reached while reading length prefix",
+                                ))));
+                            }
+                            filled += n;
                         }
+                    }
 
-                        let message = Message::from_bytes(&payload);
-                        return Poll::Ready(Some(Ok(message)));
+                    let payload_len = D::decode_length(&this.scratch[..filled]) as usize;
+                    let prefix_len = filled;
+                    if D::padding_field_size() > 0 {
+                        ensure_capacity(this.scratch, prefix_len + D::padding_field_size());
+                        state = State::ReadingPaddingField {
+                            prefix_len,
+                            payload_len,
+                            filled: 0,
+                        };
+                    } else {
+                        ensure_capacity(this.scratch, prefix_len + payload_len);
+                        state = State::ReadingPayload {
+                            payload_start: prefix_len,
+                            payload_len,
+                            filled: 0,
+                            explicit_padding: None,
+                        };
                     }
-                    Reading::Message => {
-                        if let Err(e) =
-                            futures::ready!(read_exact(Reading::Message, &mut buf[read..], cx))
+                }
+                State::ReadingPaddingField {
+                    prefix_len,
+                    payload_len,
+                    mut filled,
+                } => {
+                    let target = D::padding_field_size();
+                    while filled < target {
+                        let n = match this
+                            .reader
+                            .read(&mut this.scratch[prefix_len + filled..prefix_len + target])
+                            .poll_unpin(cx)?
                         {
-                            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                return Poll::Ready(None);
+                            Poll::Ready(n) => n,
+                            Poll::Pending => {
+                                *this.state = State::ReadingPaddingField {
+                                    prefix_len,
+                                    payload_len,
+                                    filled,
+                                };
+                                return Poll::Pending;
                             }
-                            return Some(Err(e.into())).into();
-                        }
-                        *this.read_bytes += *this.message_length as u64 + 4;
-                        if *this.read_bytes >= (*this.message_length + 4) as u64 {
-                            // This is a temp solution, to the padding that Direct I/O requires.
-                            // Later on, we could encode that information in our batch header
-                            // for example Header { batch_length: usize, padding: usize }
-                            // and use the padding to advance the reader further.
-                            /*
-                            let total_batch_length = *this.batch_length + RETAINED_BATCH_OVERHEAD;
-                            let adjusted_size = io::val_align_up(total_batch_length, *this.sector_size);
-                            */
-                            let total_batch_length = (*this.message_length + 4) as u64;
-                            let adjusted_size =
-                                dma_buf::val_align_up(total_batch_length, *this.sector_size);
-                            let diff = adjusted_size - total_batch_length;
-                            this.reader.consume_unpin(diff as _);
-                            *this.message_length = 0;
+                        };
+                        if n == 0 {
+                            return Poll::Ready(Some(Err(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "EOF reached while reading padding field",
+                            ))));
                         }
+                        filled += n;
+                    }
 
-                        let message: Message = Message::from_bytes(&buf);
-                        return Poll::Ready(Some(Ok(message)));
+                    let padding = D::decode_padding(&this.scratch[prefix_len..prefix_len + target]);
+                    let payload_start = prefix_len + target;
+                    ensure_capacity(this.scratch, payload_start + payload_len);
+                    state = State::ReadingPayload {
+                        payload_start,
+                        payload_len,
+                        filled: 0,
+                        explicit_padding: Some(padding),
+                    };
+                }
+                State::ReadingPayload {
+                    payload_start,
+                    payload_len,
+                    mut filled,
+                    explicit_padding,
+                } => {
+                    while filled < payload_len {
+                        let n = match this
+                            .reader
+                            .read(
+                                &mut this.scratch
+                                    [payload_start + filled..payload_start + payload_len],
+                            )
+                            .poll_unpin(cx)?
+                        {
+                            Poll::Ready(n) => n,
+                            Poll::Pending => {
+                                *this.state = State::ReadingPayload {
+                                    payload_start,
+                                    payload_len,
+                                    filled,
+                                    explicit_padding,
+                                };
+                                return Poll::Pending;
+                            }
+                        };
+                        if n == 0 {
+                            return Poll::Ready(Some(Err(std::io::Error::new(
+                                std::io::ErrorKind::UnexpectedEof,
+                                "EOF reached while reading payload",
+                            ))));
+                        }
+                        filled += n;
                     }
+
+                    let remaining = match explicit_padding {
+                        Some(padding) => padding as usize,
+                        None => {
+                            let total = (payload_start + payload_len) as u64;
+                            let aligned = dma_buf::val_align_up(total, *this.sector_size);
+                            (aligned - total) as usize
+                        }
+                    };
+                    state = State::Trailing {
+                        remaining,
+                        payload_start,
+                        payload_len,
+                    };
+                }
+                State::Trailing {
+                    mut remaining,
+                    payload_start,
+                    payload_len,
+                } => {
+                    return match drain_padding(this.reader.as_mut(), &mut remaining, cx) {
+                        Poll::Ready(Ok(())) => {
+                            let item = match D::decode_item(
+                                &this.scratch[payload_start..payload_start + payload_len],
+                            ) {
+                                Ok(item) => item,
+                                Err(e) => return Poll::Ready(Some(Err(e))),
+                            };
+                            Poll::Ready(Some(Ok(item)))
+                        }
+                        Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+                        Poll::Pending => {
+                            *this.state = State::Trailing {
+                                remaining,
+                                payload_start,
+                                payload_len,
+                            };
+                            Poll::Pending
+                        }
+                    };
                 }
             }
         }
+    }
+}
 
-        let mut buf = [0u8; 4];
-        if let Err(e) = futures::ready!(read_exact(Reading::Length, &mut buf, cx)) {
-            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                return Poll::Ready(None);
+
+#[cfg(test)]
+mod tests {
+    use futures::{io::Cursor, SinkExt, StreamExt};
+
+    use super::*;
+    use crate::{
+        decoder::{NetworkEndianU32, VarintDelimited},
+        sink::MessageSink,
+    };
+
+    const SECTOR_SIZE: u64 = 64;
+
+    /// Zero-pads a hand-built record with no explicit padding field out to
+    /// the next `sector_size` boundary, like `NetworkEndianU32` and
+    /// `VarintDelimited` expect `MessageStream` to derive on its own.
+    fn pad_to_sector(mut buf: Vec<u8>, sector_size: u64) -> Vec<u8> {
+        let aligned = dma_buf::val_align_up(buf.len() as u64, sector_size);
+        buf.resize(aligned as usize, 0);
+        buf
+    }
+
+    async fn write_messages(messages: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut sink = MessageSink::new(Cursor::new(&mut buf), SECTOR_SIZE);
+            for message in messages {
+                sink.send(Message::from_bytes(message)).await.unwrap();
             }
-            return Some(Err(e.into())).into();
+            sink.close().await.unwrap();
         }
-        let length = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-        *this.message_length = length;
+        buf
+    }
+
+    #[test]
+    fn round_trips_through_sink_and_stream() {
+        futures::executor::block_on(async {
+            let buf = write_messages(&[b"hello", b"world!"]).await;
 
-        let mut payload = vec![0u8; length as _];
-        if let Err(e) = futures::ready!(read_exact(Reading::Message, &mut payload, cx)) {
-            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                return Poll::Ready(None);
+            let mut stream: MessageStream<_, LeU32Length<Message>> =
+                MessageStream::new(Cursor::new(buf), SECTOR_SIZE);
+
+            assert_eq!(stream.next().await.unwrap().unwrap().to_bytes(), b"hello");
+            assert_eq!(stream.next().await.unwrap().unwrap().to_bytes(), b"world!");
+            assert!(stream.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_corrupt_trailer_padding() {
+        futures::executor::block_on(async {
+            let mut buf = write_messages(&[b"hello"]).await;
+            let last = buf.len() - 1;
+            buf[last] = 0xFF;
+
+            let mut stream: MessageStream<_, LeU32Length<Message>> =
+                MessageStream::new(Cursor::new(buf), SECTOR_SIZE);
+
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn reads_network_endian_length_prefix_with_derived_padding() {
+        futures::executor::block_on(async {
+            let payload = b"hello";
+            let mut buf = (payload.len() as u32).to_be_bytes().to_vec();
+            buf.extend_from_slice(payload);
+            let buf = pad_to_sector(buf, SECTOR_SIZE);
+
+            let mut stream: MessageStream<_, NetworkEndianU32<Message>> =
+                MessageStream::new(Cursor::new(buf), SECTOR_SIZE);
+
+            assert_eq!(stream.next().await.unwrap().unwrap().to_bytes(), payload);
+            assert!(stream.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn reads_multi_byte_varint_length_prefix_with_derived_padding() {
+        futures::executor::block_on(async {
+            // A payload long enough that its length needs a two-byte LEB128
+            // varint (>= 128), to exercise the continuation-bit loop past
+            // its first byte.
+            let payload = vec![0xABu8; 200];
+            let mut buf = Vec::new();
+            let mut len = payload.len() as u64;
+            loop {
+                let mut byte = (len & 0x7f) as u8;
+                len >>= 7;
+                if len != 0 {
+                    byte |= 0x80;
+                }
+                buf.push(byte);
+                if len == 0 {
+                    break;
+                }
             }
-            return Some(Err(e.into())).into();
-        }
-        *this.read_bytes += length as u64 + 4;
-        if *this.read_bytes >= (*this.message_length + 4) as u64 {
-            // This is a temp solution, to the padding that Direct I/O requires.
-            // Later on, we could encode that information in our batch header
-            // for example Header { batch_length: usize, padding: usize }
-            // and use the padding to advance the reader further.
-            /*
-            let total_batch_length = *this.batch_length + RETAINED_BATCH_OVERHEAD;
-            let adjusted_size = io::val_align_up(total_batch_length, *this.sector_size);
-            */
-            let total_batch_length = (*this.message_length + 4) as u64;
-            let adjusted_size = dma_buf::val_align_up(total_batch_length, *this.sector_size);
-            let diff = adjusted_size - total_batch_length;
-            this.reader.consume_unpin(diff as _);
-            *this.message_length = 0;
-        }
+            buf.extend_from_slice(&payload);
+            let buf = pad_to_sector(buf, SECTOR_SIZE);
+
+            let mut stream: MessageStream<_, VarintDelimited<Message>> =
+                MessageStream::new(Cursor::new(buf), SECTOR_SIZE);
 
-        let message = Message::from_bytes(&payload);
-        Poll::Ready(Some(Ok(message)))
+            assert_eq!(
+                stream.next().await.unwrap().unwrap().to_bytes(),
+                payload.as_slice()
+            );
+            assert!(stream.next().await.is_none());
+        });
     }
 }